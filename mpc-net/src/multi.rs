@@ -1,22 +1,461 @@
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use futures::future::join_all;
+use hkdf::Hkdf;
 use lazy_static::lazy_static;
 use log::debug;
-use rayon::prelude::*;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::Mutex;
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, Mutex, MutexGuard};
+use x25519_dalek::{PublicKey, StaticSecret};
 //use crossbeam::scope;
 
-#[macro_use]
+/// Maximum size of a single encrypted frame's plaintext payload.
+///
+/// Frames larger than this are not supported; callers are expected to chunk
+/// large payloads (the channel API here never sends more than a few
+/// megabytes at a time).
+const MAX_FRAME_LEN: usize = 1 << 30;
+
+/// How long a `Peer` may go without activity before it's considered idle and
+/// due for a keepalive frame.
+const PING_PERIOD: Duration = Duration::from_secs(5);
+
+/// Default number of times a single exchange will re-dial a peer after a
+/// transient I/O error before giving up.
+const DEFAULT_RETRY_BUDGET: usize = 5;
+
+/// How long the higher-id side of a pair waits for the lower-id side's dial
+/// to arrive via the accept router before falling back to dialing out
+/// itself. See `open_pair` for why both sides need to agree on which of the
+/// two possible physical connections they keep.
+const ACCEPT_PREFERENCE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Errors produced by the channel layer. Every fallible entry point in this
+/// module returns one of these instead of panicking, so a caller embedding
+/// the prover in a larger service can recover or report the failure instead
+/// of the whole process aborting.
+#[derive(Debug)]
+pub enum ChannelError {
+    /// Underlying transport failure (connect, accept, or a framed
+    /// read/write) that wasn't resolved by reconnection.
+    Io(std::io::Error),
+    /// The Noise-KK handshake with a peer did not complete, usually because
+    /// the peer does not hold the private key for its configured static
+    /// public key.
+    Handshake(String),
+    /// A line in the host config file didn't parse as `addr pubkey`.
+    Config(String),
+    /// The king's scatter in `recv_from_king` was given a payload of the
+    /// wrong size for some peer.
+    SizeMismatch {
+        peer: usize,
+        expected: usize,
+        got: usize,
+    },
+    /// `connect_to_all` gave up waiting for a peer to become reachable.
+    Timeout(String),
+    /// A decrypted frame didn't match the size the reader expected of it
+    /// (e.g. `SecureStream::read_exact` got a frame of the wrong length).
+    FrameMismatch { expected: usize, got: usize },
+    /// A frame's advertised length exceeds `MAX_FRAME_LEN`; refused before
+    /// allocating a buffer for it.
+    FrameTooLarge { len: u64, max: usize },
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::Io(e) => write!(f, "channel I/O error: {}", e),
+            ChannelError::Handshake(s) => write!(f, "handshake failed: {}", s),
+            ChannelError::Config(s) => write!(f, "bad host config: {}", s),
+            ChannelError::SizeMismatch {
+                peer,
+                expected,
+                got,
+            } => write!(
+                f,
+                "king scatter size mismatch for peer {}: expected {} bytes, got {}",
+                peer, expected, got
+            ),
+            ChannelError::Timeout(s) => write!(f, "channel setup timed out: {}", s),
+            ChannelError::FrameMismatch { expected, got } => write!(
+                f,
+                "frame size mismatch: expected {} bytes, got {}",
+                expected, got
+            ),
+            ChannelError::FrameTooLarge { len, max } => write!(
+                f,
+                "frame length {} exceeds the maximum of {} bytes",
+                len, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChannelError {}
+
+impl From<std::io::Error> for ChannelError {
+    fn from(e: std::io::Error) -> Self {
+        ChannelError::Io(e)
+    }
+}
+
+/// Tag carried by every `SecureStream` frame's plaintext (ahead of the
+/// payload, inside the AEAD envelope) so a keepalive can be told apart from
+/// a real data frame and discarded by the reader instead of being handed to
+/// a caller expecting `m` bytes of payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameKind {
+    Data = 0,
+    Keepalive = 1,
+}
+
+impl FrameKind {
+    fn from_tag(tag: u8) -> Result<Self, ChannelError> {
+        match tag {
+            0 => Ok(FrameKind::Data),
+            1 => Ok(FrameKind::Keepalive),
+            other => Err(ChannelError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown frame type tag {}", other),
+            ))),
+        }
+    }
+}
+
+/// Liveness state of a `Peer`'s connection, mirroring the connect /
+/// steady-state / idle lifecycle of a long-running P2P link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    /// No live stream yet; a (re)connection attempt is or will be underway.
+    Connecting,
+    /// Stream is up and has seen activity within `PING_PERIOD`.
+    Connected,
+    /// Stream is up but has been quiet for longer than `PING_PERIOD`; a
+    /// keepalive is due.
+    Idle,
+}
+
+/// True if `err` is the kind of error a dropped TCP connection produces,
+/// meaning the peer is worth re-dialing rather than treating as fatal.
+fn is_transient(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Same as `is_transient`, but for the `ChannelError` a `SecureStream`
+/// exchange can now fail with: only a transient I/O error underneath is
+/// worth retrying, never a frame-format problem.
+fn is_transient_err(err: &ChannelError) -> bool {
+    matches!(err, ChannelError::Io(e) if is_transient(e))
+}
+
+/// A secure, authenticated transport wrapping a tokio `TcpStream`.
+///
+/// Handshake and framing follow a Noise-KK-style pattern: both parties know
+/// each other's static X25519 public key ahead of time (from the host
+/// config), exchange ephemeral public keys, and mix `ee`/`es`/`se`/`ss` DH
+/// results through HKDF to derive independent send/recv ChaCha20-Poly1305
+/// keys. Every `write_all`/`read_exact` afterwards operates on
+/// length-prefixed, encrypted frames with a monotonically increasing
+/// per-direction 96-bit nonce, so replays and reordering are detected as
+/// AEAD failures.
+pub struct SecureStream {
+    stream: TcpStream,
+    send_key: ChaCha20Poly1305,
+    recv_key: ChaCha20Poly1305,
+    send_nonce: u64,
+    recv_nonce: u64,
+}
+
+fn nonce_from_counter(counter: u64) -> Nonce {
+    let mut bytes = [0u8; 12];
+    bytes[4..].copy_from_slice(&counter.to_le_bytes());
+    *Nonce::from_slice(&bytes)
+}
+
+impl SecureStream {
+    /// Perform a Noise-KK handshake over `stream` and wrap it.
+    ///
+    /// `initiator` distinguishes which side's ephemeral key is sent first;
+    /// `my_static`/`their_static` are the long-lived identity keys
+    /// configured for this peer pair. The handshake aborts (returns
+    /// `ChannelError::Handshake`) if a final key-confirmation exchange
+    /// (`confirm_handshake`) doesn't check out, which in practice means the
+    /// peer does not hold the private key for its configured static public
+    /// key.
+    async fn handshake(
+        mut stream: TcpStream,
+        initiator: bool,
+        my_static: &StaticSecret,
+        their_static: &PublicKey,
+    ) -> Result<Self, ChannelError> {
+        let my_static_pub = PublicKey::from(my_static);
+        // `StaticSecret` rather than `EphemeralSecret` purely because its
+        // `diffie_hellman` borrows `self`: the handshake needs two DH
+        // operations (`ee` and one of `es`/`se`) on the same ephemeral key,
+        // which `EphemeralSecret::diffie_hellman`'s consuming signature
+        // can't support. It is still used for a single handshake only.
+        let my_eph = StaticSecret::new(rand::rngs::OsRng);
+        let my_eph_pub = PublicKey::from(&my_eph);
+
+        let their_eph_pub = if initiator {
+            stream.write_all(my_eph_pub.as_bytes()).await?;
+            let mut buf = [0u8; 32];
+            stream.read_exact(&mut buf).await?;
+            PublicKey::from(buf)
+        } else {
+            let mut buf = [0u8; 32];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(my_eph_pub.as_bytes()).await?;
+            PublicKey::from(buf)
+        };
+
+        let ee = my_eph.diffie_hellman(&their_eph_pub);
+        let (es, se) = if initiator {
+            (
+                my_eph.diffie_hellman(their_static),
+                my_static.diffie_hellman(&their_eph_pub),
+            )
+        } else {
+            (
+                my_static.diffie_hellman(&their_eph_pub),
+                my_eph.diffie_hellman(their_static),
+            )
+        };
+        let ss = my_static.diffie_hellman(their_static);
+
+        let mut ikm = Vec::with_capacity(32 * 4);
+        ikm.extend_from_slice(ee.as_bytes());
+        ikm.extend_from_slice(es.as_bytes());
+        ikm.extend_from_slice(se.as_bytes());
+        ikm.extend_from_slice(ss.as_bytes());
+        let hk = Hkdf::<Sha256>::new(None, &ikm);
+
+        let mut init_to_resp = [0u8; 32];
+        let mut resp_to_init = [0u8; 32];
+        hk.expand(b"collaborative-zksnark init->resp", &mut init_to_resp)
+            .map_err(|_| ChannelError::Handshake("HKDF expand failed".into()))?;
+        hk.expand(b"collaborative-zksnark resp->init", &mut resp_to_init)
+            .map_err(|_| ChannelError::Handshake("HKDF expand failed".into()))?;
+
+        let (send_bytes, recv_bytes) = if initiator {
+            (init_to_resp, resp_to_init)
+        } else {
+            (resp_to_init, init_to_resp)
+        };
+        let send_key = ChaCha20Poly1305::new(Key::from_slice(&send_bytes));
+        let recv_key = ChaCha20Poly1305::new(Key::from_slice(&recv_bytes));
+
+        let _ = my_static_pub; // identity is authenticated implicitly via `ss`/`es`/`se`
+
+        // Confirm both sides actually derived the same keys before treating
+        // the handshake as complete. A static-key mismatch (the peer isn't
+        // who `their_static` says they are) makes each side derive
+        // different send/recv keys, which would otherwise only surface
+        // later, opaquely, as an "AEAD decryption failed" on the first real
+        // frame. Exchanging one confirmation frame under a nonce reserved
+        // for the handshake (never reused by `write_all`/`read_frame`,
+        // which start counting from 0) catches that right here instead.
+        Self::confirm_handshake(&mut stream, &send_key, &recv_key).await?;
+
+        Ok(Self {
+            stream,
+            send_key,
+            recv_key,
+            send_nonce: 0,
+            recv_nonce: 0,
+        })
+    }
+
+    /// Exchange one AEAD-protected confirmation message under `send_key`/
+    /// `recv_key` and a nonce outside the range `write_all`/`read_frame`
+    /// ever use, so a key mismatch is reported as `ChannelError::Handshake`
+    /// immediately rather than as a confusing decryption failure on the
+    /// first real frame.
+    async fn confirm_handshake(
+        stream: &mut TcpStream,
+        send_key: &ChaCha20Poly1305,
+        recv_key: &ChaCha20Poly1305,
+    ) -> Result<(), ChannelError> {
+        const CONFIRM: &[u8] = b"collaborative-zksnark handshake confirm";
+        let confirm_nonce = nonce_from_counter(u64::MAX);
+
+        let ciphertext = send_key
+            .encrypt(&confirm_nonce, CONFIRM)
+            .expect("encryption failure");
+        stream
+            .write_all(&(ciphertext.len() as u64).to_le_bytes())
+            .await?;
+        stream.write_all(&ciphertext).await?;
+
+        let mut len_bytes = [0u8; 8];
+        stream.read_exact(&mut len_bytes).await?;
+        let len = u64::from_le_bytes(len_bytes) as usize;
+        if len > 4096 {
+            return Err(ChannelError::Handshake(
+                "handshake confirmation frame implausibly large".into(),
+            ));
+        }
+        let mut their_ciphertext = vec![0u8; len];
+        stream.read_exact(&mut their_ciphertext).await?;
+        let plaintext = recv_key
+            .decrypt(&confirm_nonce, their_ciphertext.as_ref())
+            .map_err(|_| {
+                ChannelError::Handshake(
+                    "key confirmation failed: peer's static key doesn't match the one configured for it"
+                        .into(),
+                )
+            })?;
+        if plaintext != CONFIRM {
+            return Err(ChannelError::Handshake(
+                "key confirmation mismatch".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
+        self.stream.set_nodelay(nodelay)
+    }
+
+    async fn write_tagged(&mut self, kind: FrameKind, plaintext: &[u8]) -> Result<(), ChannelError> {
+        if plaintext.len() > MAX_FRAME_LEN {
+            return Err(ChannelError::FrameTooLarge {
+                len: plaintext.len() as u64,
+                max: MAX_FRAME_LEN,
+            });
+        }
+        let mut tagged = Vec::with_capacity(1 + plaintext.len());
+        tagged.push(kind as u8);
+        tagged.extend_from_slice(plaintext);
+        let nonce = nonce_from_counter(self.send_nonce);
+        self.send_nonce += 1;
+        let ciphertext = self
+            .send_key
+            .encrypt(&nonce, tagged.as_ref())
+            .expect("encryption failure");
+        self.stream
+            .write_all(&(ciphertext.len() as u64).to_le_bytes())
+            .await?;
+        Ok(self.stream.write_all(&ciphertext).await?)
+    }
+
+    pub async fn write_all(&mut self, plaintext: &[u8]) -> Result<(), ChannelError> {
+        self.write_tagged(FrameKind::Data, plaintext).await
+    }
+
+    /// Send an empty keepalive frame, tagged so the reader on the other end
+    /// can tell it apart from a real, data-carrying frame and transparently
+    /// discard it instead of handing it to whatever real exchange calls
+    /// `read_exact`/`read_frame` next.
+    pub async fn write_keepalive(&mut self) -> Result<(), ChannelError> {
+        self.write_tagged(FrameKind::Keepalive, &[]).await
+    }
+
+    /// Read one self-describing frame (length header + ciphertext), decrypt
+    /// it, and return its type tag and payload, whatever size the payload
+    /// turns out to be.
+    ///
+    /// The length header is untrusted cleartext read before the frame is
+    /// authenticated, so it's checked against `MAX_FRAME_LEN` before we
+    /// allocate a buffer for it — otherwise a hostile or corrupted peer
+    /// could advertise a length near `u64::MAX` and force an outsized
+    /// allocation on the receiving side.
+    async fn read_tagged_frame(&mut self) -> Result<(FrameKind, Vec<u8>), ChannelError> {
+        let mut len_bytes = [0u8; 8];
+        self.stream.read_exact(&mut len_bytes).await?;
+        let len = u64::from_le_bytes(len_bytes);
+        // The ciphertext is the payload plus a 1-byte type tag and a 16-byte
+        // AEAD tag; `MAX_FRAME_LEN` bounds the payload alone.
+        if len > (MAX_FRAME_LEN + 1 + 16) as u64 {
+            return Err(ChannelError::FrameTooLarge {
+                len,
+                max: MAX_FRAME_LEN,
+            });
+        }
+        let mut ciphertext = vec![0u8; len as usize];
+        self.stream.read_exact(&mut ciphertext).await?;
+        let nonce = nonce_from_counter(self.recv_nonce);
+        self.recv_nonce += 1;
+        let mut tagged = self.recv_key.decrypt(&nonce, ciphertext.as_ref()).map_err(|_| {
+            ChannelError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "AEAD decryption failed",
+            ))
+        })?;
+        if tagged.is_empty() {
+            return Err(ChannelError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "frame missing type tag",
+            )));
+        }
+        let kind = FrameKind::from_tag(tagged.remove(0))?;
+        Ok((kind, tagged))
+    }
+
+    /// Read one self-describing data frame and return its payload, whatever
+    /// size it turns out to be; any keepalive frames encountered along the
+    /// way are transparently consumed and discarded.
+    pub async fn read_frame(&mut self) -> Result<Vec<u8>, ChannelError> {
+        loop {
+            let (kind, payload) = self.read_tagged_frame().await?;
+            if let FrameKind::Data = kind {
+                return Ok(payload);
+            }
+        }
+    }
+
+    /// Read a frame whose size is already known and fixed by the caller,
+    /// e.g. the `m`-byte payloads exchanged by `broadcast`. Returns
+    /// `ChannelError::FrameMismatch` instead of panicking if the frame that
+    /// comes back doesn't match the caller's expected size.
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ChannelError> {
+        let plaintext = self.read_frame().await?;
+        if plaintext.len() != buf.len() {
+            return Err(ChannelError::FrameMismatch {
+                expected: buf.len(),
+                got: plaintext.len(),
+            });
+        }
+        buf.copy_from_slice(&plaintext);
+        Ok(())
+    }
+}
+
 lazy_static! {
     pub static ref CONNECTIONS: Mutex<Connections> = Mutex::new(Connections::default());
+    /// Small runtime backing the blocking API; the MPC protocol code calls
+    /// `broadcast`/`send_to_king`/`recv_from_king` from plain synchronous
+    /// call sites, so those wrappers drive the async implementation to
+    /// completion here rather than asking every caller to become async.
+    static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Runtime::new()
+        .expect("failed to start tokio runtime for mpc-net");
 }
 
-/// Macro for locking the FieldChannel singleton in the current scope.
-macro_rules! get_ch {
-    () => {
-        CONNECTIONS.lock().expect("Poisoned FieldChannel")
-    };
+/// Acquire the global `Connections` lock.
+///
+/// `CONNECTIONS` is a `tokio::sync::Mutex`, which (unlike `std::sync::Mutex`)
+/// cannot become poisoned by a panicking holder, so this never fails today.
+/// It still returns a `Result` so the fallible channel API has one uniform
+/// failure surface instead of an `.expect` buried at every call site.
+async fn lock_connections() -> Result<MutexGuard<'static, Connections>, ChannelError> {
+    Ok(CONNECTIONS.lock().await)
 }
 
 #[derive(Default, Clone)]
@@ -25,12 +464,20 @@ pub struct Stats {
     pub bytes_recv: u64,
     pub king_exchanges: u64,
     pub broadcasts: u64,
+    /// Number of times a peer connection was transparently re-established
+    /// after a transient I/O error.
+    pub reconnections: u64,
 }
 
 pub struct Peer {
     pub id: usize,
     pub addr: SocketAddr,
-    pub stream: Option<TcpStream>,
+    /// Long-lived X25519 identity key the peer is expected to authenticate
+    /// with during the handshake; comes from the host config file.
+    pub pubkey: PublicKey,
+    pub stream: Option<SecureStream>,
+    pub state: PeerState,
+    last_activity: Instant,
 }
 
 #[derive(Default)]
@@ -38,6 +485,13 @@ pub struct Connections {
     pub id: usize,
     pub peers: Vec<Peer>,
     pub stats: Stats,
+    /// Our own static X25519 identity key, used to authenticate ourselves
+    /// to every peer during the handshake in `connect_to_all`.
+    my_identity: Option<StaticSecret>,
+    /// Number of times an exchange may re-dial a given peer after a
+    /// transient error before giving up and returning an error.
+    retry_budget: usize,
+    reconnections: Arc<AtomicU64>,
 }
 
 impl std::default::Default for Peer {
@@ -45,233 +499,830 @@ impl std::default::Default for Peer {
         Self {
             id: 0,
             addr: "127.0.0.1:8000".parse().unwrap(),
+            pubkey: PublicKey::from([0u8; 32]),
             stream: None,
+            state: PeerState::Connecting,
+            last_activity: Instant::now(),
         }
     }
 }
 
+/// Re-dial (or re-accept) a single peer's connection and redo the
+/// handshake. Unlike the initial `connect_to_all` setup there is no
+/// fallback and no nonce tie-break: the lower id always dials and plays the
+/// Noise-KK initiator, and the higher id always accepts.
+async fn redial_peer(
+    own_id: usize,
+    own_addr: SocketAddr,
+    my_identity: &StaticSecret,
+    peer: &mut Peer,
+) -> Result<(), ChannelError> {
+    peer.stream = None;
+    peer.state = PeerState::Connecting;
+    let secure = if own_id < peer.id {
+        let stream = TcpStream::connect(peer.addr).await?;
+        stream.set_nodelay(true)?;
+        SecureStream::handshake(stream, true, my_identity, &peer.pubkey).await?
+    } else {
+        let listener = TcpListener::bind(own_addr).await?;
+        let (stream, _addr) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+        SecureStream::handshake(stream, false, my_identity, &peer.pubkey).await?
+    };
+    peer.stream = Some(secure);
+    peer.state = PeerState::Connected;
+    peer.last_activity = Instant::now();
+    Ok(())
+}
+
+/// Accept incoming dials on `own_addr` and route each one, by the dialer's
+/// id (sent as a cleartext 8-byte prelude before any handshake), to whatever
+/// entry is currently registered for that id in `routes`. Runs until the
+/// caller aborts the task (once every pair in `connect_to_all` has settled).
+/// A connection for an id with no registered route (the pair already
+/// resolved its connection some other way, or hasn't started its attempt
+/// yet) is simply dropped; `open_pair` re-registers a fresh route on every
+/// retry, so this isn't a race against a one-shot registration.
+async fn accept_router(
+    own_addr: SocketAddr,
+    routes: Arc<Mutex<HashMap<usize, oneshot::Sender<TcpStream>>>>,
+) -> Result<(), ChannelError> {
+    let listener = TcpListener::bind(own_addr).await?;
+    loop {
+        let (mut stream, _addr) = listener.accept().await?;
+        let mut id_bytes = [0u8; 8];
+        if stream.read_exact(&mut id_bytes).await.is_err() {
+            continue;
+        }
+        let from_id = u64::from_le_bytes(id_bytes) as usize;
+        if let Some(tx) = routes.lock().await.remove(&from_id) {
+            let _ = tx.send(stream);
+        }
+    }
+}
+
+/// Obtain the one TCP connection a pair will use, either our own dial to
+/// `peer_addr` or that peer's dial handed to us by `accept_router`, and run
+/// the nonce `tie_break` on it. The two sources are tried one after the
+/// other, not concurrently (see `open_pair_attempt`). A pair's two endpoints each
+/// see two candidate physical connections (their own outbound dial, and the
+/// peer's dial arriving via `accept_router`); racing them independently on
+/// each side (e.g. via `select!`, picking whichever is locally ready first)
+/// lets the two ends disagree about which one to keep, since there's no
+/// guarantee both sides' races resolve to the same underlying TCP
+/// connection. Instead both sides apply the same rule, so they agree without
+/// needing to talk about it: the lower id is the connection's designated
+/// dialer, and the higher id waits on the accept router for exactly that
+/// dial. The higher id falls back to dialing out itself if that dial hasn't
+/// arrived within `ACCEPT_PREFERENCE_WINDOW`, and the lower id falls back to
+/// the accept router only if its own dial fails (unreachable peer,
+/// one-directional NAT, or just an unlucky race against ordinary startup
+/// skew/latency). If the two sides end up holding different connections,
+/// the tie-break on them fails, and the whole attempt is retried up to
+/// `retry_budget` times rather than immediately failing every other peer's
+/// connection setup in `connect_to_all`. Returns the stream and whether we
+/// won the tie-break (and so play the Noise-KK initiator).
+async fn open_pair(
+    own_id: usize,
+    peer_id: usize,
+    peer_addr: SocketAddr,
+    routes: Arc<Mutex<HashMap<usize, oneshot::Sender<TcpStream>>>>,
+    retry_budget: usize,
+) -> Result<(TcpStream, bool), ChannelError> {
+    let mut attempts = 0;
+    loop {
+        let (tx, rx) = oneshot::channel();
+        routes.lock().await.insert(peer_id, tx);
+        let attempt: Result<(TcpStream, bool), ChannelError> = async {
+            let mut stream = open_pair_attempt(own_id, peer_id, peer_addr, rx).await?;
+            let i_am_initiator = tie_break(&mut stream).await?;
+            Ok((stream, i_am_initiator))
+        }
+        .await;
+        match attempt {
+            Ok(pair) => return Ok(pair),
+            Err(e) if attempts < retry_budget => {
+                attempts += 1;
+                debug!(
+                    "open_pair: retrying connection to peer {}: {} (retry {}/{})",
+                    peer_id, e, attempts, retry_budget
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn open_pair_attempt(
+    own_id: usize,
+    peer_id: usize,
+    peer_addr: SocketAddr,
+    accepted: oneshot::Receiver<TcpStream>,
+) -> Result<TcpStream, ChannelError> {
+    let dial = async {
+        let mut ms_waited = 0;
+        loop {
+            match TcpStream::connect(peer_addr).await {
+                Ok(mut stream) => {
+                    stream.write_all(&(own_id as u64).to_le_bytes()).await?;
+                    return Ok(stream);
+                }
+                Err(e) if is_transient(&e) || e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    ms_waited += 50;
+                    if ms_waited > 30_000 {
+                        return Err(ChannelError::Timeout(format!(
+                            "could not reach peer {} in 30s",
+                            peer_id
+                        )));
+                    }
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                Err(e) => return Err(ChannelError::Io(e)),
+            }
+        }
+    };
+    if own_id < peer_id {
+        match dial.await {
+            Ok(stream) => Ok(stream),
+            Err(dial_err) => accepted.await.map_err(|_| dial_err),
+        }
+    } else {
+        match tokio::time::timeout(ACCEPT_PREFERENCE_WINDOW, accepted).await {
+            Ok(Ok(stream)) => Ok(stream),
+            Ok(Err(_)) => Err(ChannelError::Handshake(format!(
+                "accept channel for peer {} dropped",
+                peer_id
+            ))),
+            Err(_) => dial.await,
+        }
+    }
+}
+
+/// Exchange random nonces over a freshly-opened, not-yet-authenticated
+/// stream and deterministically decide who plays the Noise-KK initiator:
+/// the side with the larger nonce. This replaces the old fixed
+/// lower-id-dials convention, which no longer applies once both sides may
+/// dial and accept for the same pair.
+async fn tie_break(stream: &mut TcpStream) -> Result<bool, ChannelError> {
+    let my_nonce: u64 = rand::random();
+    stream.write_all(&my_nonce.to_le_bytes()).await?;
+    let mut their_bytes = [0u8; 8];
+    stream.read_exact(&mut their_bytes).await?;
+    let their_nonce = u64::from_le_bytes(their_bytes);
+    Ok(my_nonce > their_nonce)
+}
+
 impl Connections {
-    /// Given a path and the `id` of oneself, initialize the structure
-    fn init_from_path(&mut self, path: &str, id: usize) {
-        let f = BufReader::new(File::open(path).expect("host configuration path"));
+    /// Given a path and the `id` of oneself, initialize the structure.
+    ///
+    /// Each non-empty line of the config is `addr pubkey`, where `pubkey` is
+    /// the peer's hex-encoded X25519 static public key. `my_identity` is our
+    /// own static secret key, used to prove our identity to every peer.
+    fn init_from_path(
+        &mut self,
+        path: &str,
+        id: usize,
+        my_identity: StaticSecret,
+    ) -> Result<(), ChannelError> {
+        let f = BufReader::new(File::open(path)?);
         let mut peer_id = 0;
         for line in f.lines() {
-            let line = line.unwrap();
+            let line = line?;
             let trimmed = line.trim();
             if trimmed.len() > 0 {
-                let addr: SocketAddr = trimmed
+                let mut parts = trimmed.split_whitespace();
+                let addr_str = parts
+                    .next()
+                    .ok_or_else(|| ChannelError::Config(format!("missing address: {}", trimmed)))?;
+                let key_str = parts.next().ok_or_else(|| {
+                    ChannelError::Config(format!(
+                        "missing pubkey; lines are `addr pubkey`: {}",
+                        trimmed
+                    ))
+                })?;
+                let addr: SocketAddr = addr_str
                     .parse()
-                    .unwrap_or_else(|e| panic!("bad socket address: {}:\n{}", trimmed, e));
+                    .map_err(|e| ChannelError::Config(format!("bad socket address {}: {}", addr_str, e)))?;
+                let key_bytes = hex::decode(key_str)
+                    .map_err(|e| ChannelError::Config(format!("pubkey must be hex-encoded: {}", e)))?;
+                if key_bytes.len() != 32 {
+                    return Err(ChannelError::Config(format!(
+                        "pubkey must be 32 bytes, got {}",
+                        key_bytes.len()
+                    )));
+                }
+                let mut key_arr = [0u8; 32];
+                key_arr.copy_from_slice(&key_bytes);
                 let peer = Peer {
                     id: peer_id,
                     addr,
+                    pubkey: PublicKey::from(key_arr),
                     stream: None,
+                    state: PeerState::Connecting,
+                    last_activity: Instant::now(),
                 };
                 self.peers.push(peer);
                 peer_id += 1;
             }
         }
-        assert!(id < self.peers.len());
+        if id >= self.peers.len() {
+            return Err(ChannelError::Config(format!(
+                "own id {} is out of range for {} configured peers",
+                id,
+                self.peers.len()
+            )));
+        }
         self.id = id;
+        self.my_identity = Some(my_identity);
+        self.retry_budget = DEFAULT_RETRY_BUDGET;
+        Ok(())
     }
-    fn connect_to_all(&mut self) {
+    /// Establish a secure stream with every other configured peer.
+    ///
+    /// All pairs are set up concurrently instead of in the old sequential
+    /// rounds: one shared listener on our own address routes incoming dials
+    /// (tagged with the dialer's id) to whichever peer is still waiting for a
+    /// connection, while we dial every higher-id peer ourselves (and a
+    /// lower-id peer only as a fallback, see `open_pair`). Both endpoints of
+    /// a pair pick between the two candidate connections the same way (see
+    /// `open_pair`), so they agree on a single winner without a failure
+    /// mode where each side keeps a different half of two dropped
+    /// connections; a tiny nonce tie-break on the winning stream then
+    /// decides which side plays the Noise-KK initiator role, since the
+    /// connection's dialer isn't always the lower id once a fallback path
+    /// is taken.
+    async fn connect_to_all(&mut self) -> Result<(), ChannelError> {
         let n = self.peers.len();
-        for from_id in 0..n {
-            for to_id in (from_id + 1)..n {
-                debug!("{} to {}", from_id, to_id);
-                if self.id == from_id {
-                    let to_addr = self.peers[to_id].addr;
-                    debug!("Contacting {}", to_id);
-                    let stream = loop {
-                        let mut ms_waited = 0;
-                        match TcpStream::connect(to_addr) {
-                            Ok(s) => break s,
-                            Err(e) => match e.kind() {
-                                std::io::ErrorKind::ConnectionRefused
-                                | std::io::ErrorKind::ConnectionReset => {
-                                    ms_waited += 10;
-                                    std::thread::sleep(std::time::Duration::from_millis(10));
-                                    if ms_waited % 3_000 == 0 {
-                                        debug!("Still waiting");
-                                    } else if ms_waited > 30_000 {
-                                        panic!("Could not find peer in 30s");
-                                    }
-                                }
-                                _ => {
-                                    panic!("Error during FieldChannel::new: {}", e);
-                                }
-                            },
-                        }
-                    };
-                    stream.set_nodelay(true).unwrap();
-                    self.peers[to_id].stream = Some(stream);
-                } else if self.id == to_id {
-                    debug!("Awaiting {}", from_id);
-                    let listener = TcpListener::bind(self.peers[self.id].addr).unwrap();
-                    let (stream, _addr) = listener.accept().unwrap();
-                    stream.set_nodelay(true).unwrap();
-                    self.peers[from_id].stream = Some(stream);
-                }
-            }
-            // Sender for next round waits for note from this sender to prevent race on receipt.
-            if from_id + 1 < n {
-                if self.id == from_id {
-                    self.peers[self.id + 1]
-                        .stream
-                        .as_mut()
-                        .unwrap()
-                        .write_all(&[0u8])
-                        .unwrap();
-                } else if self.id == from_id + 1 {
-                    self.peers[self.id - 1]
-                        .stream
-                        .as_mut()
-                        .unwrap()
-                        .read_exact(&mut [0u8])
-                        .unwrap();
-                }
+        let own_id = self.id;
+        let own_addr = self.peers[own_id].addr;
+        let my_identity = self.my_identity.clone().unwrap();
+
+        let routes = Arc::new(Mutex::new(HashMap::new()));
+        let accept_task = tokio::spawn(accept_router(own_addr, routes.clone()));
+        let retry_budget = self.retry_budget;
+
+        let pair_setups = (0..n).filter(|&id| id != own_id).map(|id| {
+            let peer_addr = self.peers[id].addr;
+            let their_pubkey = self.peers[id].pubkey;
+            let my_identity = my_identity.clone();
+            let routes = routes.clone();
+            async move {
+                let (stream, i_am_initiator) =
+                    open_pair(own_id, id, peer_addr, routes, retry_budget).await?;
+                let secure =
+                    SecureStream::handshake(stream, i_am_initiator, &my_identity, &their_pubkey)
+                        .await?;
+                Ok::<(usize, SecureStream), ChannelError>((id, secure))
             }
+        });
+        let results = join_all(pair_setups).await;
+        accept_task.abort();
+
+        for result in results {
+            let (id, secure) = result?;
+            self.peers[id].stream = Some(secure);
+            self.peers[id].state = PeerState::Connected;
+            self.peers[id].last_activity = Instant::now();
         }
         for id in 0..n {
-            if id != self.id {
-                assert!(self.peers[id].stream.is_some());
+            if id != own_id && self.peers[id].stream.is_none() {
+                return Err(ChannelError::Handshake(format!(
+                    "no stream established with peer {}",
+                    id
+                )));
             }
         }
+        Ok(())
     }
     fn am_king(&self) -> bool {
         self.id == 0
     }
-    fn broadcast(&mut self, bytes_out: &[u8]) -> Vec<Vec<u8>> {
+    async fn broadcast(&mut self, bytes_out: &[u8]) -> Result<Vec<Vec<u8>>, ChannelError> {
         let m = bytes_out.len();
         let own_id = self.id;
+        let own_addr = self.peers[own_id].addr;
+        let my_identity = self.my_identity.clone().unwrap();
+        let retry_budget = self.retry_budget;
+        let reconnections = self.reconnections.clone();
         self.stats.bytes_sent += ((self.peers.len() - 1) * m) as u64;
         self.stats.bytes_recv += ((self.peers.len() - 1) * m) as u64;
         self.stats.broadcasts += 1;
-        self.peers
-            .par_iter_mut()
-            .enumerate()
-            .map(|(id, peer)| {
-                let mut bytes_in = vec![0u8; m];
-                if id < own_id {
+        let my_identity = &my_identity;
+        let reconnections = &reconnections;
+        let exchanges = self.peers.iter_mut().enumerate().map(|(id, peer)| async move {
+            let mut bytes_in = vec![0u8; m];
+            if id == own_id {
+                bytes_in.copy_from_slice(bytes_out);
+                return Ok(bytes_in);
+            }
+            let mut attempts = 0;
+            loop {
+                let result: Result<(), ChannelError> = async {
                     let stream = peer.stream.as_mut().unwrap();
-                    stream.read_exact(&mut bytes_in[..]).unwrap();
-                    stream.write_all(bytes_out).unwrap();
-                } else if id == own_id {
-                    bytes_in.copy_from_slice(bytes_out);
-                } else {
+                    if id < own_id {
+                        stream.read_exact(&mut bytes_in[..]).await?;
+                        stream.write_all(bytes_out).await?;
+                    } else {
+                        stream.write_all(bytes_out).await?;
+                        stream.read_exact(&mut bytes_in[..]).await?;
+                    }
+                    Ok(())
+                }
+                .await;
+                match result {
+                    Ok(()) => {
+                        peer.last_activity = Instant::now();
+                        peer.state = PeerState::Connected;
+                        break;
+                    }
+                    Err(e) if is_transient_err(&e) && attempts < retry_budget => {
+                        attempts += 1;
+                        debug!(
+                            "broadcast: transient error with peer {}: {} (retry {}/{})",
+                            id, e, attempts, retry_budget
+                        );
+                        redial_peer(own_id, own_addr, my_identity, peer).await?;
+                        reconnections.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(bytes_in)
+        });
+        join_all(exchanges).await.into_iter().collect()
+    }
+    /// Like `broadcast`, but each party's contribution may be a different
+    /// size: the length is sent as an 8-byte header ahead of the payload
+    /// (the same self-describing frame `SecureStream` already uses on the
+    /// wire), so callers no longer need to agree on `m` out of band.
+    async fn broadcast_var(&mut self, bytes_out: &[u8]) -> Result<Vec<Vec<u8>>, ChannelError> {
+        let own_id = self.id;
+        let own_addr = self.peers[own_id].addr;
+        let my_identity = self.my_identity.clone().unwrap();
+        let retry_budget = self.retry_budget;
+        let reconnections = self.reconnections.clone();
+        self.stats.broadcasts += 1;
+        self.stats.bytes_sent += ((self.peers.len() - 1) * (bytes_out.len() + 8)) as u64;
+        let my_identity = &my_identity;
+        let reconnections = &reconnections;
+        let exchanges = self.peers.iter_mut().enumerate().map(|(id, peer)| async move {
+            if id == own_id {
+                return Ok((0, bytes_out.to_vec()));
+            }
+            let mut attempts = 0;
+            loop {
+                let result: Result<Vec<u8>, ChannelError> = async {
                     let stream = peer.stream.as_mut().unwrap();
-                    stream.write_all(bytes_out).unwrap();
-                    stream.read_exact(&mut bytes_in[..]).unwrap();
-                };
-                bytes_in
-            })
-            .collect()
+                    if id < own_id {
+                        let bytes_in = stream.read_frame().await?;
+                        stream.write_all(bytes_out).await?;
+                        Ok(bytes_in)
+                    } else {
+                        stream.write_all(bytes_out).await?;
+                        let bytes_in = stream.read_frame().await?;
+                        Ok(bytes_in)
+                    }
+                }
+                .await;
+                match result {
+                    Ok(bytes_in) => {
+                        peer.last_activity = Instant::now();
+                        peer.state = PeerState::Connected;
+                        return Ok(((bytes_in.len() + 8) as u64, bytes_in));
+                    }
+                    Err(e) if is_transient_err(&e) && attempts < retry_budget => {
+                        attempts += 1;
+                        debug!(
+                            "broadcast_var: transient error with peer {}: {} (retry {}/{})",
+                            id, e, attempts, retry_budget
+                        );
+                        redial_peer(own_id, own_addr, my_identity, peer).await?;
+                        reconnections.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        });
+        let results: Result<Vec<(u64, Vec<u8>)>, ChannelError> =
+            join_all(exchanges).await.into_iter().collect();
+        let results = results?;
+        self.stats.bytes_recv += results.iter().map(|(len, _)| len).sum::<u64>();
+        Ok(results.into_iter().map(|(_, bytes)| bytes).collect())
     }
-    fn send_to_king(&mut self, bytes_out: &[u8]) -> Option<Vec<Vec<u8>>> {
+    async fn send_to_king(
+        &mut self,
+        bytes_out: &[u8],
+    ) -> Result<Option<Vec<Vec<u8>>>, ChannelError> {
         let m = bytes_out.len();
         let own_id = self.id;
+        let own_addr = self.peers[own_id].addr;
+        let my_identity = self.my_identity.clone().unwrap();
+        let retry_budget = self.retry_budget;
+        let reconnections = self.reconnections.clone();
         self.stats.king_exchanges += 1;
         if self.am_king() {
             self.stats.bytes_recv += ((self.peers.len() - 1) * m) as u64;
-            Some(
-                self.peers
-                    .par_iter_mut()
-                    .enumerate()
-                    .map(|(id, peer)| {
-                        let mut bytes_in = vec![0u8; m];
-                        if id == own_id {
-                            bytes_in.copy_from_slice(bytes_out);
-                        } else {
-                            let stream = peer.stream.as_mut().unwrap();
-                            stream.read_exact(&mut bytes_in[..]).unwrap();
-                        };
-                        bytes_in
-                    })
-                    .collect(),
-            )
+            let my_identity = &my_identity;
+            let reconnections = &reconnections;
+            let gathers = self.peers.iter_mut().enumerate().map(|(id, peer)| async move {
+                let mut bytes_in = vec![0u8; m];
+                if id == own_id {
+                    bytes_in.copy_from_slice(bytes_out);
+                    return Ok(bytes_in);
+                }
+                let mut attempts = 0;
+                loop {
+                    let result = peer.stream.as_mut().unwrap().read_exact(&mut bytes_in[..]).await;
+                    match result {
+                        Ok(()) => {
+                            peer.last_activity = Instant::now();
+                            peer.state = PeerState::Connected;
+                            break;
+                        }
+                        Err(e) if is_transient_err(&e) && attempts < retry_budget => {
+                            attempts += 1;
+                            redial_peer(own_id, own_addr, my_identity, peer).await?;
+                            reconnections.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(bytes_in)
+            });
+            let gathered: Result<Vec<Vec<u8>>, ChannelError> =
+                join_all(gathers).await.into_iter().collect();
+            Ok(Some(gathered?))
         } else {
             self.stats.bytes_sent += m as u64;
-            self.peers[0]
-                .stream
-                .as_mut()
-                .unwrap()
-                .write_all(bytes_out)
-                .unwrap();
-            None
+            let king = &mut self.peers[0];
+            let mut attempts = 0;
+            loop {
+                let result = king.stream.as_mut().unwrap().write_all(bytes_out).await;
+                match result {
+                    Ok(()) => {
+                        king.last_activity = Instant::now();
+                        king.state = PeerState::Connected;
+                        break;
+                    }
+                    Err(e) if is_transient_err(&e) && attempts < retry_budget => {
+                        attempts += 1;
+                        redial_peer(own_id, own_addr, &my_identity, king).await?;
+                        reconnections.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(None)
         }
     }
-    fn recv_from_king(&mut self, bytes_out: Option<Vec<Vec<u8>>>) -> Vec<u8> {
+    async fn recv_from_king(
+        &mut self,
+        bytes_out: Option<Vec<Vec<u8>>>,
+    ) -> Result<Vec<u8>, ChannelError> {
         let own_id = self.id;
+        let own_addr = self.peers[own_id].addr;
+        let my_identity = self.my_identity.clone().unwrap();
+        let retry_budget = self.retry_budget;
+        let reconnections = self.reconnections.clone();
         self.stats.king_exchanges += 1;
         if self.am_king() {
             let bytes_out = bytes_out.unwrap();
             let m = bytes_out[0].len();
             let bytes_size = (m as u64).to_le_bytes();
             self.stats.bytes_sent += ((self.peers.len() - 1) * (m + 8)) as u64;
-            self.peers
-                .par_iter_mut()
+            let my_identity = &my_identity;
+            let reconnections = &reconnections;
+            let bytes_out = &bytes_out;
+            let scatters = self
+                .peers
+                .iter_mut()
                 .enumerate()
                 .filter(|p| p.0 != own_id)
-                .for_each(|(id, peer)| {
-                    let stream = peer.stream.as_mut().unwrap();
-                    assert_eq!(bytes_out[id].len(), m);
-                    stream.write_all(&bytes_size).unwrap();
-                    stream.write_all(&bytes_out[id]).unwrap();
+                .map(|(id, peer)| async move {
+                    if bytes_out[id].len() != m {
+                        return Err(ChannelError::SizeMismatch {
+                            peer: id,
+                            expected: m,
+                            got: bytes_out[id].len(),
+                        });
+                    }
+                    let mut attempts = 0;
+                    loop {
+                        let result: Result<(), ChannelError> = async {
+                            let stream = peer.stream.as_mut().unwrap();
+                            stream.write_all(&bytes_size).await?;
+                            stream.write_all(&bytes_out[id]).await
+                        }
+                        .await;
+                        match result {
+                            Ok(()) => {
+                                peer.last_activity = Instant::now();
+                                peer.state = PeerState::Connected;
+                                break;
+                            }
+                            Err(e) if is_transient_err(&e) && attempts < retry_budget => {
+                                attempts += 1;
+                                redial_peer(own_id, own_addr, my_identity, peer).await?;
+                                reconnections.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    Ok(())
                 });
-            bytes_out[own_id].clone()
+            join_all(scatters)
+                .await
+                .into_iter()
+                .collect::<Result<Vec<()>, ChannelError>>()?;
+            Ok(bytes_out[own_id].clone())
         } else {
-            let stream = self.peers[0].stream.as_mut().unwrap();
-            let mut bytes_size = [0u8; 8];
-            stream.read_exact(&mut bytes_size).unwrap();
-            let m = u64::from_le_bytes(bytes_size) as usize;
-            self.stats.bytes_recv += m as u64;
-            let mut bytes_in = vec![0u8; m];
-            stream.read_exact(&mut bytes_in).unwrap();
-            bytes_in
+            let king = &mut self.peers[0];
+            let mut attempts = 0;
+            let bytes_in = loop {
+                let result: Result<Vec<u8>, ChannelError> = async {
+                    let stream = king.stream.as_mut().unwrap();
+                    let mut bytes_size = [0u8; 8];
+                    stream.read_exact(&mut bytes_size).await?;
+                    let m = u64::from_le_bytes(bytes_size) as usize;
+                    let mut bytes_in = vec![0u8; m];
+                    stream.read_exact(&mut bytes_in).await?;
+                    Ok(bytes_in)
+                }
+                .await;
+                match result {
+                    Ok(bytes_in) => {
+                        king.last_activity = Instant::now();
+                        king.state = PeerState::Connected;
+                        break bytes_in;
+                    }
+                    Err(e) if is_transient_err(&e) && attempts < retry_budget => {
+                        attempts += 1;
+                        redial_peer(own_id, own_addr, &my_identity, king).await?;
+                        reconnections.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            self.stats.bytes_recv += bytes_in.len() as u64;
+            Ok(bytes_in)
         }
     }
     fn uninit(&mut self) {
         for p in &mut self.peers {
             p.stream = None;
+            p.state = PeerState::Connecting;
+        }
+    }
+    /// Send a tagged, empty keepalive frame to every peer that has been idle
+    /// longer than `PING_PERIOD`, so flaky WAN links notice a dead
+    /// connection before the next real exchange needs it.
+    ///
+    /// The `FrameKind::Keepalive` tag is what lets the peer's `read_exact`/
+    /// `read_frame` tell this apart from a real data frame and discard it,
+    /// rather than handing it to whatever exchange calls those next and
+    /// getting a size mismatch.
+    async fn heartbeat(&mut self) {
+        for peer in &mut self.peers {
+            if peer.id == self.id {
+                continue;
+            }
+            if peer.last_activity.elapsed() < PING_PERIOD {
+                continue;
+            }
+            peer.state = PeerState::Idle;
+            if let Some(stream) = peer.stream.as_mut() {
+                if stream.write_keepalive().await.is_ok() {
+                    peer.last_activity = Instant::now();
+                    peer.state = PeerState::Connected;
+                }
+            }
         }
     }
 }
 
-#[inline]
-pub fn init_from_path(path: &str, id: usize) {
-    let mut ch = get_ch!();
-    ch.init_from_path(path, id);
-    ch.connect_to_all();
+fn load_identity(identity_path: &str) -> Result<StaticSecret, ChannelError> {
+    let identity_hex = std::fs::read_to_string(identity_path)?;
+    let identity_bytes = hex::decode(identity_hex.trim())
+        .map_err(|e| ChannelError::Config(format!("identity key must be hex: {}", e)))?;
+    if identity_bytes.len() != 32 {
+        return Err(ChannelError::Config(format!(
+            "identity key must be 32 bytes, got {}",
+            identity_bytes.len()
+        )));
+    }
+    let mut identity_arr = [0u8; 32];
+    identity_arr.copy_from_slice(&identity_bytes);
+    Ok(StaticSecret::from(identity_arr))
+}
+
+/// Initialize the global channel from a host config at `path` and our own
+/// static identity secret key at `identity_path` (32 bytes of hex-encoded
+/// X25519 scalar).
+async fn init_from_path_impl(path: &str, id: usize, identity_path: &str) -> Result<(), ChannelError> {
+    let my_identity = load_identity(identity_path)?;
+    let mut ch = lock_connections().await?;
+    ch.init_from_path(path, id, my_identity)?;
+    ch.connect_to_all().await?;
+    drop(ch);
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(PING_PERIOD);
+        loop {
+            ticker.tick().await;
+            heartbeat_async().await;
+        }
+    });
     debug!("Connected");
+    Ok(())
 }
 
 #[inline]
-pub fn broadcast(bytes_out: &[u8]) -> Vec<Vec<u8>> {
-    get_ch!().broadcast(bytes_out)
+pub fn init_from_path(path: &str, id: usize, identity_path: &str) -> Result<(), ChannelError> {
+    RUNTIME.block_on(init_from_path_impl(path, id, identity_path))
 }
 
+/// Async variant of [`init_from_path`] for callers already driving a tokio
+/// runtime, so they don't pay for a nested `block_on`.
 #[inline]
-pub fn send_to_king(bytes_out: &[u8]) -> Option<Vec<Vec<u8>>> {
-    get_ch!().send_to_king(bytes_out)
+pub async fn init_from_path_async(
+    path: &str,
+    id: usize,
+    identity_path: &str,
+) -> Result<(), ChannelError> {
+    init_from_path_impl(path, id, identity_path).await
 }
 
 #[inline]
-pub fn recv_from_king(bytes_out: Option<Vec<Vec<u8>>>) -> Vec<u8> {
-    get_ch!().recv_from_king(bytes_out)
+pub fn broadcast(bytes_out: &[u8]) -> Result<Vec<Vec<u8>>, ChannelError> {
+    RUNTIME.block_on(broadcast_async(bytes_out))
+}
+
+#[inline]
+pub async fn broadcast_async(bytes_out: &[u8]) -> Result<Vec<Vec<u8>>, ChannelError> {
+    lock_connections().await?.broadcast(bytes_out).await
+}
+
+#[inline]
+pub fn broadcast_var(bytes_out: &[u8]) -> Result<Vec<Vec<u8>>, ChannelError> {
+    RUNTIME.block_on(broadcast_var_async(bytes_out))
+}
+
+#[inline]
+pub async fn broadcast_var_async(bytes_out: &[u8]) -> Result<Vec<Vec<u8>>, ChannelError> {
+    lock_connections().await?.broadcast_var(bytes_out).await
+}
+
+#[inline]
+pub fn send_to_king(bytes_out: &[u8]) -> Result<Option<Vec<Vec<u8>>>, ChannelError> {
+    RUNTIME.block_on(send_to_king_async(bytes_out))
+}
+
+#[inline]
+pub async fn send_to_king_async(
+    bytes_out: &[u8],
+) -> Result<Option<Vec<Vec<u8>>>, ChannelError> {
+    lock_connections().await?.send_to_king(bytes_out).await
+}
+
+#[inline]
+pub fn recv_from_king(bytes_out: Option<Vec<Vec<u8>>>) -> Result<Vec<u8>, ChannelError> {
+    RUNTIME.block_on(recv_from_king_async(bytes_out))
+}
+
+#[inline]
+pub async fn recv_from_king_async(
+    bytes_out: Option<Vec<Vec<u8>>>,
+) -> Result<Vec<u8>, ChannelError> {
+    lock_connections().await?.recv_from_king(bytes_out).await
 }
 
 #[inline]
 pub fn am_king() -> bool {
-    get_ch!().am_king()
+    RUNTIME.block_on(async { CONNECTIONS.lock().await.am_king() })
 }
 
 #[inline]
 pub fn uninit() {
-    get_ch!().uninit();
+    RUNTIME.block_on(async { CONNECTIONS.lock().await.uninit() });
     debug!("Unconnected");
 }
 
 #[inline]
 pub fn stats() -> Stats {
-    get_ch!().stats.clone()
+    RUNTIME.block_on(async {
+        let ch = CONNECTIONS.lock().await;
+        let mut stats = ch.stats.clone();
+        stats.reconnections = ch.reconnections.load(Ordering::Relaxed);
+        stats
+    })
+}
+
+/// Set how many times a single exchange will re-dial a peer after a
+/// transient I/O error before giving up. Must be called after
+/// [`init_from_path`].
+#[inline]
+pub fn set_retry_budget(budget: usize) {
+    RUNTIME.block_on(async { CONNECTIONS.lock().await.retry_budget = budget });
+}
+
+/// Send keepalive frames to any peer idle longer than `PING_PERIOD`.
+/// `init_from_path`/`init_from_path_async` already spawn a background task
+/// that calls this on an interval for the life of the session, so most
+/// callers never need to invoke it directly; it's exposed for callers
+/// driving their own timer instead.
+#[inline]
+pub fn heartbeat() {
+    RUNTIME.block_on(heartbeat_async())
+}
+
+#[inline]
+pub async fn heartbeat_async() {
+    CONNECTIONS.lock().await.heartbeat().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spin up a loopback pair of `SecureStream`s, each side using its own
+    /// static key with the other side's public key configured as the peer,
+    /// exactly as `redial_peer` and `connect_to_all` do.
+    async fn loopback_pair() -> (SecureStream, SecureStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let initiator_static = StaticSecret::new(rand::rngs::OsRng);
+        let responder_static = StaticSecret::new(rand::rngs::OsRng);
+        let initiator_pub = PublicKey::from(&initiator_static);
+        let responder_pub = PublicKey::from(&responder_static);
+
+        let accept = tokio::spawn(async move {
+            let (stream, _addr) = listener.accept().await.unwrap();
+            SecureStream::handshake(stream, false, &responder_static, &initiator_pub)
+                .await
+                .unwrap()
+        });
+        let dial = TcpStream::connect(addr).await.unwrap();
+        let initiator = SecureStream::handshake(dial, true, &initiator_static, &responder_pub)
+            .await
+            .unwrap();
+        let responder = accept.await.unwrap();
+        (initiator, responder)
+    }
+
+    #[tokio::test]
+    async fn handshake_and_frame_round_trip() {
+        let (mut a, mut b) = loopback_pair().await;
+
+        a.write_all(b"hello from a").await.unwrap();
+        let mut buf = [0u8; "hello from a".len()];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello from a");
+
+        b.write_all(b"hello from b").await.unwrap();
+        let received = a.read_frame().await.unwrap();
+        assert_eq!(received, b"hello from b");
+    }
+
+    #[tokio::test]
+    async fn keepalive_frame_is_transparently_discarded() {
+        let (mut a, mut b) = loopback_pair().await;
+
+        a.write_keepalive().await.unwrap();
+        a.write_all(b"real payload").await.unwrap();
+
+        let mut buf = [0u8; "real payload".len()];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"real payload");
+    }
+
+    #[tokio::test]
+    async fn handshake_fails_on_mismatched_peer_key() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let initiator_static = StaticSecret::new(rand::rngs::OsRng);
+        let responder_static = StaticSecret::new(rand::rngs::OsRng);
+        // The initiator is configured with the wrong public key for the
+        // responder, so the two sides derive different send/recv keys.
+        let wrong_pub = PublicKey::from(&StaticSecret::new(rand::rngs::OsRng));
+        let initiator_pub = PublicKey::from(&initiator_static);
+
+        let accept = tokio::spawn(async move {
+            let (stream, _addr) = listener.accept().await.unwrap();
+            SecureStream::handshake(stream, false, &responder_static, &initiator_pub).await
+        });
+        let dial = TcpStream::connect(addr).await.unwrap();
+        let initiator_result =
+            SecureStream::handshake(dial, true, &initiator_static, &wrong_pub).await;
+        let responder_result = accept.await.unwrap();
+
+        assert!(matches!(
+            initiator_result,
+            Err(ChannelError::Handshake(_))
+        ));
+        assert!(matches!(
+            responder_result,
+            Err(ChannelError::Handshake(_))
+        ));
+    }
 }